@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 
+use chrono::NaiveDate;
 use clap::{Args, Parser};
 use log::LevelFilter;
 
@@ -13,6 +14,9 @@ pub struct Arguments {
     #[command(flatten)]
     pub authentication: Authentication,
 
+    #[command(flatten)]
+    pub filters: SearchFilters,
+
     #[arg(help = "The name of the host to connect with")]
     pub hostname: String,
 
@@ -20,7 +24,34 @@ pub struct Arguments {
     pub port: u16,
 
     #[arg(help = "A path on the filesystem to write to")]
-    pub output: PathBuf
+    pub output: PathBuf,
+
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Zip, help = "The layout to write fetched messages in")]
+    pub format: OutputFormat,
+
+    #[arg(long = "no-compress", help = "Do not negotiate COMPRESS=DEFLATE even if the server supports it")]
+    pub no_compress: bool,
+
+    #[arg(long = "restore", help = "Replay a previously written archive back onto the server instead of fetching mail")]
+    pub restore: bool,
+
+    #[arg(short = 'j', long = "jobs", default_value_t = 1, help = "The number of concurrent IMAP sessions to fetch mailboxes with")]
+    pub jobs: usize
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[value(name = "zip")]
+    Zip,
+
+    #[value(name = "tar")]
+    Tar,
+
+    #[value(name = "maildir")]
+    Maildir,
+
+    #[value(name = "mbox")]
+    Mbox
 }
 
 #[derive(Args)]
@@ -45,10 +76,64 @@ pub struct Authentication {
     #[arg(short = 'p', long = "password", help = "The password to use for authentication")]
     pub password: Option<String>,
 
+    #[arg(long = "oauth2", help = "Authenticate with an OAuth2 access token instead of a password")]
+    pub oauth2: bool,
+
+    #[arg(long = "oauth2-token", help = "The OAuth2 access token to use with --oauth2 (falls back to IMAP_OAUTH_TOKEN)")]
+    pub oauth2_token: Option<String>,
+
+    #[arg(long = "oauth2-mechanism", value_enum, default_value_t = Oauth2Mechanism::XOAuth2, help = "The SASL mechanism to use with --oauth2")]
+    pub oauth2_mechanism: Oauth2Mechanism,
+
+    #[arg(long = "starttls", help = "Use STARTTLS instead of implicit TLS")]
+    pub starttls: bool,
+
     #[arg(short = 'i', long = "insecure", help = "Accept invalid TLS certificates")]
     pub insecure: bool
 }
 
+/// Server-side `SEARCH` filters for selective backups. When none of these
+/// are set, every mailbox is fetched in full (subject to the incremental
+/// manifest); when any are set, only the matching messages are fetched and
+/// the manifest is left untouched, since a filtered run is a one-off export
+/// rather than a full pass over the mailbox.
+#[derive(Args, Default)]
+#[group()]
+pub struct SearchFilters {
+    #[arg(long = "since", value_name = "DATE", help = "Only fetch messages received on or after this date (YYYY-MM-DD)")]
+    pub since: Option<NaiveDate>,
+
+    #[arg(long = "before", value_name = "DATE", help = "Only fetch messages received before this date (YYYY-MM-DD)")]
+    pub before: Option<NaiveDate>,
+
+    #[arg(long = "seen", conflicts_with = "unseen", help = "Only fetch messages already marked \\Seen")]
+    pub seen: bool,
+
+    #[arg(long = "unseen", help = "Only fetch messages not marked \\Seen")]
+    pub unseen: bool,
+
+    #[arg(long = "larger", value_name = "BYTES", help = "Only fetch messages larger than this many bytes")]
+    pub larger: Option<u64>,
+
+    #[arg(long = "from", value_name = "ADDRESS", help = "Only fetch messages with a From header containing this text")]
+    pub from: Option<String>
+}
+
+impl SearchFilters {
+    pub fn is_active(&self) -> bool {
+        self.since.is_some() || self.before.is_some() || self.seen || self.unseen || self.larger.is_some() || self.from.is_some()
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Oauth2Mechanism {
+    #[value(name = "xoauth2")]
+    XOAuth2,
+
+    #[value(name = "oauthbearer")]
+    OAuthBearer
+}
+
 impl Verbosity {
     pub fn to_filter(&self) -> LevelFilter {
         if self.debug { LevelFilter::Trace }
@@ -0,0 +1,136 @@
+use std::{io::{self, Read, Write}, sync::{Arc, Mutex}};
+
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use imap::Session;
+use log::debug;
+
+/// Which optional IMAP extensions the server advertised, and which of them
+/// this run decided to use. Determined once, right after login, and then
+/// threaded through to every mailbox so the fetch loop never has to ask the
+/// server about its capabilities again.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    pub compress_deflate: bool,
+    pub qresync: bool,
+    pub condstore: bool,
+    pub utf8_accept: bool
+}
+
+impl Capabilities {
+    /// Reads the server's `CAPABILITY` response and records which of the
+    /// extensions this tool knows how to use are available. `allow_compress`
+    /// lets `--no-compress` opt out of `COMPRESS=DEFLATE` even when the
+    /// server advertises it.
+    pub fn negotiate<T: Read + Write>(session: &mut Session<T>, allow_compress: bool) -> imap::error::Result<Self> {
+        let capabilities = session.capabilities()?;
+
+        let negotiated = Self {
+            compress_deflate: allow_compress && capabilities.has_str("COMPRESS=DEFLATE"),
+            qresync: capabilities.has_str("QRESYNC"),
+            condstore: capabilities.has_str("CONDSTORE"),
+            utf8_accept: capabilities.has_str("UTF8=ACCEPT")
+        };
+
+        debug!("Negotiated capabilities: {negotiated:?}");
+
+        Ok(negotiated)
+    }
+}
+
+/// A `Read + Write` handle onto a shared stream, so the same underlying
+/// connection can back both halves of a `DeflateStream` without requiring
+/// the transport to be `Clone`. Uses `Arc<Mutex<T>>` rather than
+/// `Rc<RefCell<T>>` so the stream (and therefore the `Session` it backs)
+/// stays `Send` and can be handed to a `--jobs` worker thread.
+#[derive(Clone)]
+struct Shared<T>(Arc<Mutex<T>>);
+
+impl<T: Read> Read for Shared<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buffer)
+    }
+}
+
+impl<T: Write> Write for Shared<T> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Wraps an already-authenticated stream in a raw deflate codec once
+/// `COMPRESS DEFLATE` has been accepted by the server, so the rest of the
+/// session can keep talking IMAP over the same `Read`/`Write` interface.
+pub struct DeflateStream<T> {
+    decoder: DeflateDecoder<Shared<T>>,
+    encoder: DeflateEncoder<Shared<T>>
+}
+
+impl<T: Read + Write> DeflateStream<T> {
+    pub fn new(inner: T) -> Self {
+        let shared = Shared(Arc::new(Mutex::new(inner)));
+
+        Self {
+            decoder: DeflateDecoder::new(shared.clone()),
+            encoder: DeflateEncoder::new(shared, Compression::default())
+        }
+    }
+}
+
+impl<T: Read> Read for DeflateStream<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buffer)
+    }
+}
+
+impl<T: Write> Write for DeflateStream<T> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.encoder.write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// A stream that may or may not have been upgraded with `COMPRESS=DEFLATE`,
+/// so the IMAP session is backed by a single concrete type regardless of
+/// which path capability negotiation took at runtime.
+pub enum MaybeDeflate<T: Read + Write> {
+    Plain(T),
+    Deflate(DeflateStream<T>)
+}
+
+impl<T: Read + Write> MaybeDeflate<T> {
+    pub fn compressed(inner: T) -> Self {
+        Self::Deflate(DeflateStream::new(inner))
+    }
+}
+
+impl<T: Read + Write> Read for MaybeDeflate<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buffer),
+            Self::Deflate(stream) => stream.read(buffer)
+        }
+    }
+}
+
+impl<T: Read + Write> Write for MaybeDeflate<T> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buffer),
+            Self::Deflate(stream) => stream.write(buffer)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Deflate(stream) => stream.flush()
+        }
+    }
+}
@@ -1,15 +1,21 @@
-use std::{io::{Read, Write}, path::Path};
+use std::{io::{Read, Write}, path::Path, sync::Mutex};
 
-use chrono::{Local, TimeDelta};
 use imap::{types::Fetch, Session};
 use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
 use lazy_static::lazy_static;
-use log::{debug, info, warn};
-use sha2::{Digest, Sha256};
-use tar::{Builder, Header};
+use log::{debug, warn};
+
+use crate::arguments::SearchFilters;
+use crate::capabilities::Capabilities;
+use crate::manifest::{Manifest, MailboxState};
+use crate::sink::MessageSink;
 
 type IntegerResult = Result<u64, Box<dyn std::error::Error + Send + Sync>>;
-type TupleResult = Result<(u64, TimeDelta), Box<dyn std::error::Error + Send + Sync>>;
+
+/// The number of UIDs requested per `UID FETCH`, so a mailbox with millions
+/// of messages is streamed in bounded-size chunks instead of being pulled
+/// (and held in memory) in one `1:*` round trip.
+const BATCH_SIZE: u32 = 200;
 
 const PROGRESS_STYLE_TEMPLATE: &str = "[{elapsed_precise}] {wide_bar:.cyan/blue} {pos}/{len} {msg}";
 
@@ -19,7 +25,9 @@ lazy_static! {
         .progress_chars("#>-");
 }
 
-fn write_messages<W: Write>(messages: & Vec<Fetch>, name: & str, multi_progress: & MultiProgress, builder: & mut Builder<W>) -> IntegerResult {
+/// Hands every fetched message in `messages` to `sink`, which owns the
+/// on-disk representation for the chosen `--format`.
+fn write_messages(messages: &[Fetch], name: &str, multi_progress: &MultiProgress, sink: &mut dyn MessageSink) -> IntegerResult {
     let count = messages.len() as u64;
     let progress = multi_progress.add(ProgressBar::new(count));
 
@@ -31,27 +39,15 @@ fn write_messages<W: Write>(messages: & Vec<Fetch>, name: & str, multi_progress:
         let index = progress.position() + 1;
 
         if let Some(body) = message.body() {
-            let mut digest = Sha256::new();
-
-            digest.update(body);
-
-            let result = digest.finalize();
-            let hex = hex::encode(&result);
-            let filename = format!("{hex}.eml");
-            let path = &Path::new(name).join(filename);
+            let uid = message.uid.unwrap_or(0);
+            let flags = message.flags();
             let size = body.len() as u64;
 
-            let mut header = Header::new_gnu();
-
-            header.set_size(size);
-            header.set_cksum();
-            header.set_mode(0o755);
-
-            builder.append_data(&mut header, path, body)?;
+            sink.write(name, uid, &flags, body)?;
 
             total += size;
 
-            debug!("{index}/{count} -> {:?} [{}]", path, HumanBytes(size));
+            debug!("{index}/{count} -> {name}/{uid} [{}]", HumanBytes(size));
 
             // Show the current mailbox name and total amount of data fetched.
             progress.set_message(format!("{name} [{}]", HumanBytes(total)));
@@ -69,43 +65,325 @@ fn write_messages<W: Write>(messages: & Vec<Fetch>, name: & str, multi_progress:
     Ok(total)
 }
 
-pub fn fetch_messages<T: Write + Read, W: Write>(session: &mut Session<T>, multi_progress: MultiProgress, builder: &mut Builder<W>) -> TupleResult {
-    let start = Local::now();
-    let messages = session.list(Some(""), Some("*"))?;
-    let count = messages.len() as u64;
-    let progress = multi_progress.add(ProgressBar::new(count));
+/// Determines the first UID to fetch for a mailbox given its previous state.
+///
+/// When the mailbox's `UIDVALIDITY` has not changed since the last run, only
+/// UIDs newer than the last one we saw are requested. Otherwise the server
+/// has invalidated every UID we previously recorded, so the whole mailbox is
+/// re-fetched.
+fn start_uid(state: Option<&MailboxState>, uid_validity: u32) -> u32 {
+    match state {
+        Some(state) if state.uid_validity == uid_validity => state.last_uid + 1,
+        _ => 1
+    }
+}
 
-    progress.set_style(PROGRESS_STYLE.clone());
+/// Splits `start..uid_next` into `UID FETCH` ranges of at most
+/// [`BATCH_SIZE`] UIDs each, so a mailbox streams in bounded-size batches
+/// instead of being pulled in a single `1:*` round trip. Falls back to one
+/// open-ended range when the server did not report `UIDNEXT`, and returns no
+/// ranges at all when there is nothing new to fetch.
+fn batch_ranges(start: u32, uid_next: u32) -> Vec<String> {
+    if uid_next == 0 {
+        return vec![format!("{}:*", start.max(1))];
+    }
 
-    let mut total: u64 = 0;
+    if start >= uid_next { return Vec::new(); }
 
-    for name in &messages {
-        let index = progress.position() + 1;
-        let name = name.name();
+    let mut ranges = Vec::new();
+    let mut cursor = start.max(1);
+
+    while cursor < uid_next {
+        let end = (cursor + BATCH_SIZE - 1).min(uid_next - 1);
+
+        ranges.push(format!("{cursor}:{end}"));
 
-        progress.set_message(format!("{name} [{}]", HumanBytes(total)));
+        cursor = end + 1;
+    }
+
+    ranges
+}
 
-        session.examine(name)?;
+/// Builds the `UID FETCH` item list, appending a `CHANGEDSINCE` modifier when
+/// the server supports `CONDSTORE` and a previous `HIGHESTMODSEQ` watermark
+/// is available for this mailbox and `UIDVALIDITY` pair. This narrows the
+/// fetch to messages that actually changed since the last run, on top of the
+/// UID range already selected by [`start_uid`] and [`batch_ranges`]. A
+/// server that honours `CHANGEDSINCE` always tags the returned messages with
+/// their `MODSEQ`, so [`fetch_mailbox`] can read it back off each `Fetch`
+/// without asking for it explicitly.
+fn fetch_items(capabilities: &Capabilities, state: Option<&MailboxState>, uid_validity: u32) -> String {
+    match (capabilities.condstore, state) {
+        (true, Some(state)) if state.uid_validity == uid_validity => {
+            if let Some(modseq) = state.highest_modseq {
+                return format!("(RFC822 UID) (CHANGEDSINCE {modseq})");
+            }
+
+            "RFC822 UID".to_string()
+        },
+        _ => "RFC822 UID".to_string()
+    }
+}
 
-        match session.fetch("1:*", "RFC822") {
-            Ok(messages) => {
-                let size = write_messages(&messages, name, &multi_progress, builder)?;
+/// Parses the `HIGHESTMODSEQ` response code out of a raw `SELECT`
+/// response, e.g. `* OK [HIGHESTMODSEQ 90210] Highest`.
+fn parse_highest_modseq(response: &str) -> Option<u64> {
+    let after = response.split("HIGHESTMODSEQ").nth(1)?;
+    let digits = after.trim_start_matches(|c: char| !c.is_ascii_digit());
 
-                total += size;
+    digits.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+}
 
-                info!("{index}/{count} -> {name} [{}]", HumanBytes(size));
-            },
-            Err(error) => warn!("{index}/{count} -> Skipping {name}: {error}")
+/// Parses every UID named by one or more `* VANISHED (EARLIER) <uid-set>`
+/// lines in a raw `SELECT ... QRESYNC` response, expanding `first:last`
+/// ranges, so messages the server has expunged since the last run can be
+/// pruned instead of lingering in the archive forever.
+fn parse_vanished(response: &str) -> Vec<u32> {
+    let mut uids = Vec::new();
+
+    for line in response.lines() {
+        let Some(set) = line.trim_start().strip_prefix("* VANISHED (EARLIER)") else { continue; };
+
+        for part in set.trim().split(',') {
+            match part.split_once(':') {
+                Some((start, end)) => {
+                    if let (Ok(start), Ok(end)) = (start.parse::<u32>(), end.parse::<u32>()) {
+                        uids.extend(start..=end);
+                    }
+                },
+                None => if let Ok(uid) = part.parse::<u32>() { uids.push(uid); }
+            }
         }
+    }
 
-        progress.inc(1);
+    uids
+}
+
+/// Re-selects `name` with a `QRESYNC` parameter referencing the last
+/// `UIDVALIDITY`/`HIGHESTMODSEQ` pair this tool saw, so the server reports
+/// every message it has expunged since then as `VANISHED`. Each vanished UID
+/// is pruned from `sink` where the format supports it; formats that cannot
+/// remove a single already-written message are left alone and logged, since
+/// that is the honest alternative to claiming a prune that did not happen.
+/// Returns the new `HIGHESTMODSEQ`, if the server reported one.
+fn resync<T: Read + Write>(session: &mut Session<T>, name: &str, uid_validity: u32, modseq: u64, sink: &Mutex<Box<dyn MessageSink>>) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+    let command = format!("SELECT \"{}\" (QRESYNC ({uid_validity} {modseq}))", escape_quoted(name));
+    let response = session.run_command_and_read_response(&command)?;
+    let response = String::from_utf8_lossy(&response);
+
+    for uid in parse_vanished(&response) {
+        let pruned = sink.lock().unwrap().remove(name, uid)?;
+
+        if pruned { debug!("{name}/{uid}: pruned a message the server reported as VANISHED"); }
+        else { warn!("{name}/{uid}: server reported VANISHED but this format cannot prune a single message"); }
     }
 
-    let end = Local::now();
-    let elapsed = end - start;
+    Ok(parse_highest_modseq(&response))
+}
 
-    progress.finish_and_clear();
-    multi_progress.remove(&progress);
+/// Escapes `"` and `\` per the IMAP `quoted` grammar (RFC 3501 §4.3), so a
+/// value (a `--from` filter, a mailbox name) can never break out of the
+/// quotes it is interpolated into and inject additional command text.
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Builds an IMAP `SEARCH` criteria string from the `--since`/`--before`/
+/// `--seen`/`--unseen`/`--larger`/`--from` flags. Multiple criteria are
+/// simply space-separated, which `SEARCH` treats as a logical AND. Returns
+/// an empty string when no filters are set; callers should check
+/// [`SearchFilters::is_active`] first.
+fn search_criteria(filters: &SearchFilters) -> String {
+    let mut parts = Vec::new();
+
+    if let Some(date) = filters.since { parts.push(format!("SINCE {}", date.format("%d-%b-%Y"))); }
+    if let Some(date) = filters.before { parts.push(format!("BEFORE {}", date.format("%d-%b-%Y"))); }
+    if filters.seen { parts.push("SEEN".to_string()); }
+    if filters.unseen { parts.push("UNSEEN".to_string()); }
+    if let Some(bytes) = filters.larger { parts.push(format!("LARGER {bytes}")); }
+    if let Some(from) = &filters.from { parts.push(format!("FROM \"{}\"", escape_quoted(from))); }
+
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_highest_modseq_reads_the_digits_after_the_keyword() {
+        let response = "* OK [HIGHESTMODSEQ 90060128194045] Highest\r\n";
+
+        assert_eq!(parse_highest_modseq(response), Some(90060128194045));
+    }
+
+    #[test]
+    fn parse_highest_modseq_none_when_keyword_is_absent() {
+        let response = "* OK [UIDVALIDITY 1] UIDs valid\r\n* 10 EXISTS\r\n";
+
+        assert_eq!(parse_highest_modseq(response), None);
+    }
+
+    #[test]
+    fn parse_vanished_expands_ranges_and_merges_multiple_lines() {
+        let response = "\
+            * VANISHED (EARLIER) 300:310,405,411\r\n\
+            * 5 EXPUNGE\r\n\
+            * VANISHED (EARLIER) 501\r\n";
+
+        let mut uids = parse_vanished(response);
+
+        uids.sort_unstable();
+
+        let mut expected: Vec<u32> = (300..=310).collect();
+
+        expected.extend([405, 411, 501]);
+        expected.sort_unstable();
+
+        assert_eq!(uids, expected);
+    }
+
+    #[test]
+    fn parse_vanished_empty_when_there_is_no_vanished_line() {
+        let response = "* OK [HIGHESTMODSEQ 1] Highest\r\n* 1 EXISTS\r\n";
+
+        assert!(parse_vanished(response).is_empty());
+    }
+
+    #[test]
+    fn search_criteria_escapes_quotes_and_backslashes_in_from() {
+        let filters = SearchFilters { from: Some(r#"evil\" BODY "x"#.to_string()), ..Default::default() };
 
-    Ok((total, elapsed))
+        assert_eq!(search_criteria(&filters), r#"FROM "evil\\\" BODY \"x""#);
+    }
+
+    #[test]
+    fn search_criteria_joins_multiple_filters_with_and() {
+        let filters = SearchFilters { seen: true, larger: Some(1024), ..Default::default() };
+
+        assert_eq!(search_criteria(&filters), "SEEN LARGER 1024");
+    }
+
+    #[test]
+    fn batch_ranges_chunks_by_batch_size() {
+        let ranges = batch_ranges(1, 450);
+
+        assert_eq!(ranges, vec!["1:200", "201:400", "401:449"]);
+    }
+
+    #[test]
+    fn batch_ranges_returns_nothing_when_caught_up() {
+        assert!(batch_ranges(100, 100).is_empty());
+    }
+
+    #[test]
+    fn start_uid_resumes_after_last_seen_uid_when_validity_matches() {
+        let state = MailboxState { uid_validity: 7, last_uid: 42, highest_modseq: None };
+
+        assert_eq!(start_uid(Some(&state), 7), 43);
+    }
+
+    #[test]
+    fn start_uid_restarts_from_one_when_validity_changed() {
+        let state = MailboxState { uid_validity: 7, last_uid: 42, highest_modseq: None };
+
+        assert_eq!(start_uid(Some(&state), 8), 1);
+    }
+}
+
+/// Runs a server-side `UID SEARCH` for `filters` and fetches only the
+/// matching messages, in [`BATCH_SIZE`]-sized batches so a large match set
+/// still streams rather than loading in one round trip. A filtered run is
+/// an ad-hoc, selective export rather than a full pass over the mailbox, so
+/// it deliberately does not touch the incremental manifest: advancing the
+/// watermark past UIDs the filter excluded would make a later unfiltered
+/// run think those messages had already been fetched.
+fn fetch_filtered<T: Read + Write>(session: &mut Session<T>, name: &str, filters: &SearchFilters, sink: &Mutex<Box<dyn MessageSink>>, multi_progress: &MultiProgress) -> IntegerResult {
+    let criteria = search_criteria(filters);
+    let mut uids: Vec<u32> = session.uid_search(&criteria)?.into_iter().collect();
+
+    uids.sort_unstable();
+
+    if uids.is_empty() {
+        debug!("{name}: no messages match the search filters");
+
+        return Ok(0);
+    }
+
+    let mut total: u64 = 0;
+
+    for batch in uids.chunks(BATCH_SIZE as usize) {
+        let range = batch.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        let messages = session.uid_fetch(&range, "RFC822 UID")?;
+        let mut sink = sink.lock().unwrap();
+
+        total += write_messages(&messages, name, multi_progress, sink.as_mut())?;
+    }
+
+    Ok(total)
+}
+
+/// Fetches every new message in `name`, in [`BATCH_SIZE`]-sized `UID FETCH`
+/// batches, writing each batch to `sink` as soon as it arrives. `sink` and
+/// `manifest` are shared behind a mutex so several mailboxes can be fetched
+/// concurrently by `--jobs` worker threads without interleaving their writes
+/// or racing on the watermark file; each lock is held only long enough to
+/// perform the write or the save, not for the network round trip. When
+/// `filters` is active, delegates to [`fetch_filtered`] instead.
+pub fn fetch_mailbox<T: Read + Write>(session: &mut Session<T>, name: &str, sink: &Mutex<Box<dyn MessageSink>>, manifest: &Mutex<Manifest>, manifest_path: &Path, capabilities: &Capabilities, filters: &SearchFilters, multi_progress: &MultiProgress) -> IntegerResult {
+    let mailbox = session.examine(name)?;
+    let uid_validity = mailbox.uid_validity.unwrap_or(0);
+
+    if filters.is_active() {
+        return fetch_filtered(session, name, filters, sink, multi_progress);
+    }
+
+    let uid_next = mailbox.uid_next.unwrap_or(0);
+    let previous_state = manifest.lock().unwrap().get(name).cloned();
+    let start = start_uid(previous_state.as_ref(), uid_validity);
+    let items = fetch_items(capabilities, previous_state.as_ref(), uid_validity);
+    let current_state = previous_state.as_ref().filter(|state| state.uid_validity == uid_validity);
+    let mut highest_uid = current_state.map(|state| state.last_uid).unwrap_or(0);
+    let mut highest_modseq = current_state.and_then(|state| state.highest_modseq);
+    let mut total: u64 = 0;
+
+    // When the server supports QRESYNC and a HIGHESTMODSEQ from a previous
+    // run is available, re-select the mailbox with it to learn which UIDs
+    // have vanished (been expunged) since then, and prune them up front.
+    if capabilities.qresync {
+        if let Some(state) = previous_state.as_ref().filter(|state| state.uid_validity == uid_validity) {
+            if let Some(modseq) = state.highest_modseq {
+                highest_modseq = resync(session, name, uid_validity, modseq, sink)?.or(highest_modseq);
+            }
+        }
+    }
+
+    for range in batch_ranges(start, uid_next) {
+        let messages = session.uid_fetch(&range, &items)?;
+
+        if let Some(max) = messages.iter().filter_map(|message| message.uid).max() {
+            highest_uid = highest_uid.max(max);
+        }
+
+        if let Some(max) = messages.iter().filter_map(|message| message.modseq).max() {
+            highest_modseq = Some(highest_modseq.map_or(max, |current| current.max(max)));
+        }
+
+        let size = {
+            let mut sink = sink.lock().unwrap();
+
+            write_messages(&messages, name, multi_progress, sink.as_mut())?
+        };
+
+        total += size;
+
+        // Advance the watermark only after writing, so an interrupted run
+        // never skips data it never wrote.
+        let mut manifest = manifest.lock().unwrap();
+
+        manifest.set(name, MailboxState { uid_validity, last_uid: highest_uid, highest_modseq });
+        manifest.save(manifest_path)?;
+    }
+
+    Ok(total)
 }
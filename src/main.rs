@@ -1,14 +1,36 @@
 mod arguments;
 
+mod auth;
+
+mod capabilities;
+
 mod mail;
 
-use std::{env, fs::File, path::Path, process::exit};
+mod manifest;
+
+mod pool;
+
+mod restore;
 
-use arguments::{Arguments, Verbosity};
-use chrono::{Local, TimeDelta};
-use mail::{WriteTask, write_messages};
+mod sink;
+
+mod source;
+
+use std::{env, fs::File, net::TcpStream, path::Path, process::exit};
+
+use arguments::{Arguments, Oauth2Mechanism, OutputFormat, Verbosity};
+use auth::{OAuthBearer, XOAuth2, mechanism_name};
+use capabilities::{Capabilities, MaybeDeflate};
+use chrono::TimeDelta;
+use imap::Session;
+use manifest::Manifest;
+use native_tls::TlsStream;
+use pool::fetch_concurrent;
+use restore::restore_messages;
+use sink::{MaildirSink, MboxSink, MessageSink, TarSink, ZipSink};
+use source::{MaildirSource, MboxSource, MessageSource, TarSource, ZipSource};
 use clap::Parser;
-use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, MultiProgress};
 use indicatif_log_bridge::LogWrapper;
 use log::{debug, error, info, warn};
 use native_tls::TlsConnector;
@@ -16,11 +38,16 @@ use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
 
 type UnitResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 type MultiProgressResult = Result<MultiProgress, Box<dyn std::error::Error + Send + Sync>>;
+type SessionResult = Result<(Stream, Capabilities), Box<dyn std::error::Error + Send + Sync>>;
+
+/// The concrete stream type backing every `Session`, regardless of whether
+/// TLS was implicit or negotiated via `STARTTLS`: a (possibly
+/// `COMPRESS=DEFLATE`-wrapped) TLS socket.
+type Stream = MaybeDeflate<TlsStream<TcpStream>>;
 
 const IMAP_USERNAME: &str = "IMAP_USERNAME";
 const IMAP_PASSWORD: &str = "IMAP_PASSWORD";
-
-const PROGRESS_STYLE: &str = "[{elapsed_precise}] {wide_bar:.cyan/blue} {pos}/{len} {msg}";
+const IMAP_OAUTH_TOKEN: &str = "IMAP_OAUTH_TOKEN";
 
 fn setup_logging(verbosity: &Verbosity) -> MultiProgressResult {
     let filter = verbosity.to_filter();
@@ -55,98 +82,184 @@ impl Format for TimeDelta {
     }
 }
 
-fn main() -> UnitResult {
-    let arguments = Arguments::parse();
-    let multi_progress = setup_logging(&arguments.verbosity)?;
-    let style = ProgressStyle::with_template(PROGRESS_STYLE)?.progress_chars("#>-");
-
-    if dotenv::dotenv().ok() == None { debug!("Dotfile is invalid or missing"); }
-
-    let tls = TlsConnector::builder()
-        .danger_accept_invalid_certs(arguments.authentication.insecure)
-        .build()?;
-
+/// Connects and authenticates a fresh IMAP session using `arguments`,
+/// negotiating capabilities (and `COMPRESS=DEFLATE`, if allowed) exactly as
+/// the primary session does. Called once per `--jobs` worker so every
+/// worker gets its own independent connection.
+fn connect_and_authenticate(arguments: &Arguments, tls: &TlsConnector) -> SessionResult {
     let address = (arguments.hostname.as_str(), arguments.port);
 
     let mut client = if arguments.authentication.starttls {
-        imap::connect_starttls(address, &arguments.hostname, &tls)?
+        imap::connect_starttls(address, &arguments.hostname, tls)?
     }
     else {
-        imap::connect(address, &arguments.hostname, &tls)?
+        imap::connect(address, &arguments.hostname, tls)?
     };
 
     client.debug = arguments.verbosity.debug;
 
-    let username = arguments.authentication.username.or(env::var(IMAP_USERNAME).ok());
-    let password = arguments.authentication.password.or(env::var(IMAP_PASSWORD).ok());
+    let username = arguments.authentication.username.clone().or(env::var(IMAP_USERNAME).ok());
+
+    let mut session = if arguments.authentication.oauth2 {
+        let token = arguments.authentication.oauth2_token.clone().or(env::var(IMAP_OAUTH_TOKEN).ok());
+
+        if username.is_none() || token.is_none() {
+            error!("Must provide a username and an OAuth2 access token");
+
+            exit(1);
+        }
 
-    if username.is_none() || password.is_none() {
-        error!("Must provide a username and password");
+        let user = username.unwrap();
+        let token = token.unwrap();
+        let mechanism = arguments.authentication.oauth2_mechanism;
+        let name = mechanism_name(mechanism);
 
-        exit(1);
+        let result = match mechanism {
+            Oauth2Mechanism::XOAuth2 => client.authenticate(name, &XOAuth2::new(&user, &token)),
+            Oauth2Mechanism::OAuthBearer => client.authenticate(name, &OAuthBearer::new(&user, &arguments.hostname, arguments.port, &token))
+        };
+
+        result.map_err(|(error, _)| error)?
     }
+    else {
+        let password = arguments.authentication.password.clone().or(env::var(IMAP_PASSWORD).ok());
 
-    let mut session = client.login(username.unwrap(), password.unwrap()).map_err(|error| error.0)?;
+        if username.is_none() || password.is_none() {
+            error!("Must provide a username and password");
 
-    let path = Path::new(&arguments.output);
-    let file = File::options()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(path)?;
+            exit(1);
+        }
+
+        client.login(username.unwrap(), password.unwrap()).map_err(|error| error.0)?
+    };
+
+    let capabilities = Capabilities::negotiate(&mut session, !arguments.no_compress)?;
 
-    let mut writer = ZipWriter::new(file);
+    // COMPRESS=DEFLATE must be activated before any further commands are
+    // sent, so it happens immediately after negotiation and before the
+    // mailbox list is requested.
+    let stream = if capabilities.compress_deflate {
+        session.run_command_and_check_ok("COMPRESS DEFLATE")?;
 
-    let options = SimpleFileOptions::default()
-        .compression_method(CompressionMethod::Zstd)
-        .compression_level(Some(3))
-        .unix_permissions(0o755);
+        MaybeDeflate::compressed(session.into_inner())
+    }
+    else {
+        MaybeDeflate::Plain(session.into_inner())
+    };
 
-    let messages = session.list(Some(""), Some("*"))?;
-    let count = messages.len() as u64;
-    let progress = multi_progress.add(ProgressBar::new(count));
-    let mut total: u64 = 0;
+    Ok((stream, capabilities))
+}
 
-    progress.set_style(style.clone());
+fn main() -> UnitResult {
+    let arguments = Arguments::parse();
+    let multi_progress = setup_logging(&arguments.verbosity)?;
 
-    let start = Local::now();
+    if dotenv::dotenv().ok() == None { debug!("Dotfile is invalid or missing"); }
 
-    for name in &messages {
-        let index = progress.position() + 1;
-        let name = name.name();
+    let tls = TlsConnector::builder()
+        .danger_accept_invalid_certs(arguments.authentication.insecure)
+        .build()?;
 
-        progress.set_message(format!("{name} [{}]", HumanBytes(total)));
+    let (stream, capabilities) = connect_and_authenticate(&arguments, &tls)?;
+    let mut session = Session::new(stream);
 
-        session.examine(name)?;
+    let path = Path::new(&arguments.output);
 
-        match session.fetch("1:*", "RFC822") {
-            Ok(messages) => {
-                let task = WriteTask::new(&messages, name, &multi_progress, &style, &mut writer, options);
-                let size = write_messages(task)?;
+    if arguments.restore {
+        let manifest_path = Manifest::restore_path_for(path);
+        let mut manifest = Manifest::load(&manifest_path)?;
+        let mut source = build_source(&arguments.format, path)?;
 
-                total += size;
+        let (total, elapsed) = restore_messages(&mut session, multi_progress, source.as_mut(), &mut manifest, &manifest_path)?;
 
-                info!("{index}/{count} -> {name} [{}]", HumanBytes(size));
-            },
-            Err(error) => warn!("{index}/{count} -> Skipping {name}: {error}")
-        }
+        info!("Restore completed in {}", elapsed.format());
+        info!("Total restored size is {}", HumanBytes(total));
 
-        progress.inc(1);
+        return Ok(());
     }
 
-    let end = Local::now();
-    let elapsed = (end - start).format();
+    let manifest_path = Manifest::path_for(path);
+    let manifest = Manifest::load(&manifest_path)?;
 
-    info!("Copy completed in {elapsed}");
-    info!("Total copy size is {}", HumanBytes(total));
+    if arguments.format == OutputFormat::Tar && manifest_path.is_file() {
+        warn!("A tar archive only contains the messages fetched during this run; use --format zip, maildir or mbox to retain full history across runs");
+    }
 
-    progress.finish_and_clear();
-    multi_progress.remove(&progress);
-    writer.finish()?;
+    if arguments.filters.is_active() {
+        warn!("Search filters are active; matching messages are fetched but the incremental manifest is left untouched, so a later unfiltered run still captures everything");
+    }
+
+    let sink = build_sink(&arguments.format, path, &manifest_path)?;
+
+    // `--jobs` counts the primary session, so `jobs - 1` additional sessions
+    // are opened up front, each authenticating
+    // and negotiating capabilities independently; `fetch_concurrent` then
+    // distributes every mailbox across the primary session plus these.
+    let jobs = arguments.jobs.max(1);
+    let mut extra_sessions = Vec::with_capacity(jobs - 1);
+
+    for _ in 1..jobs {
+        let (stream, _) = connect_and_authenticate(&arguments, &tls)?;
+
+        extra_sessions.push(Session::new(stream));
+    }
+
+    let (total, elapsed) = fetch_concurrent(&mut session, &mut extra_sessions, multi_progress, sink, manifest, &manifest_path, &capabilities, &arguments.filters)?;
+
+    info!("Copy completed in {}", elapsed.format());
+    info!("Total copy size is {}", HumanBytes(total));
 
     Ok(())
 }
 
+/// Builds the `MessageSource` for the requested `--format`, used by
+/// `--restore` to read a previously written archive back out.
+fn build_source(format: &OutputFormat, path: &Path) -> Result<Box<dyn MessageSource>, Box<dyn std::error::Error + Send + Sync>> {
+    match format {
+        OutputFormat::Zip => Ok(Box::new(ZipSource::open(path)?)),
+        OutputFormat::Tar => Ok(Box::new(TarSource::open(path)?)),
+        OutputFormat::Maildir => Ok(Box::new(MaildirSource::open(path)?)),
+        OutputFormat::Mbox => Ok(Box::new(MboxSource::open(path)?))
+    }
+}
+
+/// Builds the `MessageSink` for the requested `--format`, handling the
+/// difference between single-file archive formats (zip, tar) and directory
+/// formats (maildir, mbox).
+fn build_sink(format: &OutputFormat, path: &Path, manifest_path: &Path) -> Result<Box<dyn MessageSink>, Box<dyn std::error::Error + Send + Sync>> {
+    match format {
+        OutputFormat::Zip => {
+            // An archive is only appended to when we already have a manifest
+            // for it and it actually exists on disk; otherwise this is a
+            // first (or from-scratch) run and the output is replaced in full.
+            let incremental = manifest_path.is_file() && path.is_file();
+
+            let file = File::options()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(!incremental)
+                .open(path)?;
+
+            let writer = if incremental { ZipWriter::new_append(file)? } else { ZipWriter::new(file) };
+
+            let options = SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Zstd)
+                .compression_level(Some(3))
+                .unix_permissions(0o755);
+
+            Ok(Box::new(ZipSink::new(writer, options)))
+        },
+        OutputFormat::Tar => {
+            let file = File::options().create(true).write(true).truncate(true).open(path)?;
+
+            Ok(Box::new(TarSink::new(file)))
+        },
+        OutputFormat::Maildir => Ok(Box::new(MaildirSink::new(path.to_path_buf()))),
+        OutputFormat::Mbox => Ok(Box::new(MboxSink::new(path.to_path_buf())?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::TimeDelta;
@@ -0,0 +1,108 @@
+use std::{collections::HashMap, fs, io::ErrorKind, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+type ManifestResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// The last known state of a single mailbox, used to decide whether the next
+/// run can fetch incrementally or must re-download the mailbox in full.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MailboxState {
+    pub uid_validity: u32,
+    pub last_uid: u32,
+    pub highest_modseq: Option<u64>
+}
+
+/// Per-mailbox watermarks persisted alongside an archive so subsequent runs
+/// only need to fetch mail that has arrived since the last copy.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    mailboxes: HashMap<String, MailboxState>
+}
+
+impl Manifest {
+    /// Derives the sidecar path for a manifest from the archive path it
+    /// accompanies, e.g. `backup.zip` -> `backup.zip.manifest.json`.
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut name = output.as_os_str().to_owned();
+
+        name.push(".manifest.json");
+
+        PathBuf::from(name)
+    }
+
+    /// Derives the sidecar path tracking restore progress for a given
+    /// source. Kept separate from [`Manifest::path_for`] so restoring from
+    /// an archive never collides with the manifest that was written while
+    /// backing it up.
+    pub fn restore_path_for(source: &Path) -> PathBuf {
+        let mut name = source.as_os_str().to_owned();
+
+        name.push(".restore.manifest.json");
+
+        PathBuf::from(name)
+    }
+
+    /// Loads a manifest from `path`, returning an empty manifest if the file
+    /// does not exist yet (i.e. this is the first run against this output).
+    pub fn load(path: &Path) -> ManifestResult<Self> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into())
+        }
+    }
+
+    /// Writes the manifest to `path` atomically (write, then rename).
+    pub fn save(&self, path: &Path) -> ManifestResult<()> {
+        let temporary_path = path.with_extension("tmp");
+        let bytes = serde_json::to_vec_pretty(self)?;
+
+        fs::write(&temporary_path, bytes)?;
+        fs::rename(&temporary_path, path)?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, mailbox: &str) -> Option<&MailboxState> {
+        self.mailboxes.get(mailbox)
+    }
+
+    pub fn set(&mut self, mailbox: &str, state: MailboxState) {
+        self.mailboxes.insert(mailbox.to_string(), state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_returns_empty_manifest_when_file_is_missing() {
+        let manifest = Manifest::load(Path::new("/nonexistent/mailcopy.manifest.json")).unwrap();
+
+        assert!(manifest.get("INBOX").is_none());
+    }
+
+    #[test]
+    fn save_then_load_round_trips_mailbox_state() {
+        let mut manifest = Manifest::default();
+        let state = MailboxState { uid_validity: 7, last_uid: 42, highest_modseq: Some(100) };
+
+        manifest.set("INBOX", state.clone());
+
+        let path = std::env::temp_dir().join(format!("mailcopy-test-{}.manifest.json", std::process::id()));
+
+        manifest.save(&path).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        let loaded_state = loaded.get("INBOX").unwrap();
+
+        assert_eq!(loaded_state.uid_validity, state.uid_validity);
+        assert_eq!(loaded_state.last_uid, state.last_uid);
+        assert_eq!(loaded_state.highest_modseq, state.highest_modseq);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    path::Path,
+    sync::Mutex,
+    thread
+};
+
+use chrono::{Local, TimeDelta};
+use imap::Session;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use lazy_static::lazy_static;
+use log::{info, warn};
+
+use crate::arguments::SearchFilters;
+use crate::capabilities::Capabilities;
+use crate::mail::fetch_mailbox;
+use crate::manifest::Manifest;
+use crate::sink::MessageSink;
+
+type TupleResult = Result<(u64, TimeDelta), Box<dyn std::error::Error + Send + Sync>>;
+
+const PROGRESS_STYLE_TEMPLATE: &str = "[{elapsed_precise}] {wide_bar:.cyan/blue} {pos}/{len} {msg}";
+
+lazy_static! {
+    static ref PROGRESS_STYLE: ProgressStyle = ProgressStyle::with_template(PROGRESS_STYLE_TEMPLATE)
+        .unwrap()
+        .progress_chars("#>-");
+}
+
+/// Lists every mailbox visible on `primary` and fetches them with `primary`
+/// plus `extra_sessions` (one worker per `--jobs` session), each worker
+/// pulling mailbox names off a shared queue until it is empty. `sink` and
+/// `manifest` are wrapped in a mutex so writes and watermark updates from
+/// different mailboxes never interleave, while the network round trips for
+/// each mailbox's batches (see [`fetch_mailbox`]) run fully in parallel.
+/// With one session and no extras this degenerates to the same behaviour as
+/// the previous strictly-serial fetch loop.
+pub fn fetch_concurrent<T: Read + Write + Send>(primary: &mut Session<T>, extra_sessions: &mut [Session<T>], multi_progress: MultiProgress, sink: Box<dyn MessageSink>, manifest: Manifest, manifest_path: &Path, capabilities: &Capabilities, filters: &SearchFilters) -> TupleResult {
+    let start = Local::now();
+    let names = primary.list(Some(""), Some("*"))?;
+    let count = names.len() as u64;
+    let queue = Mutex::new(names.iter().map(|name| name.name().to_string()).collect::<VecDeque<_>>());
+    let sink = Mutex::new(sink);
+    let manifest = Mutex::new(manifest);
+    let total = Mutex::new(0u64);
+    let overall = multi_progress.add(ProgressBar::new(count));
+
+    overall.set_style(PROGRESS_STYLE.clone());
+
+    thread::scope(|scope| {
+        let mut sessions: Vec<&mut Session<T>> = Vec::with_capacity(1 + extra_sessions.len());
+
+        sessions.push(primary);
+        sessions.extend(extra_sessions.iter_mut());
+
+        for session in sessions {
+            let queue = &queue;
+            let sink = &sink;
+            let manifest = &manifest;
+            let manifest_path = &manifest_path;
+            let total = &total;
+            let overall = &overall;
+            let multi_progress = multi_progress.clone();
+
+            scope.spawn(move || {
+                loop {
+                    let name = match queue.lock().unwrap().pop_front() {
+                        Some(name) => name,
+                        None => break
+                    };
+
+                    match fetch_mailbox(session, &name, sink, manifest, manifest_path, capabilities, filters, &multi_progress) {
+                        Ok(size) => {
+                            *total.lock().unwrap() += size;
+
+                            info!("{name} [{}]", HumanBytes(size));
+                        },
+                        Err(error) => warn!("Skipping {name}: {error}")
+                    }
+
+                    overall.inc(1);
+                }
+            });
+        }
+    });
+
+    let end = Local::now();
+    let elapsed = end - start;
+    let total = *total.lock().unwrap();
+
+    overall.finish_and_clear();
+    multi_progress.remove(&overall);
+
+    sink.into_inner().unwrap().finish()?;
+
+    Ok((total, elapsed))
+}
@@ -0,0 +1,111 @@
+use std::{io::{Read, Write}, path::Path};
+
+use chrono::{DateTime, FixedOffset, Local, TimeDelta};
+use imap::{types::Flag, Session};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::manifest::{Manifest, MailboxState};
+use crate::source::MessageSource;
+
+type TupleResult = Result<(u64, TimeDelta), Box<dyn std::error::Error + Send + Sync>>;
+
+const PROGRESS_STYLE_TEMPLATE: &str = "[{elapsed_precise}] {wide_bar:.cyan/blue} {pos}/{len} {msg}";
+
+lazy_static! {
+    static ref PROGRESS_STYLE: ProgressStyle = ProgressStyle::with_template(PROGRESS_STYLE_TEMPLATE)
+        .unwrap()
+        .progress_chars("#>-");
+}
+
+fn to_flag(name: &str) -> Flag<'static> {
+    match name {
+        "Seen" => Flag::Seen,
+        "Answered" => Flag::Answered,
+        "Flagged" => Flag::Flagged,
+        "Deleted" => Flag::Deleted,
+        "Draft" => Flag::Draft,
+        other => Flag::Custom(other.to_string().into())
+    }
+}
+
+/// Pulls the `Date:` header out of a message so it can be preserved as the
+/// IMAP `INTERNALDATE` on restore, falling back to the current time for a
+/// message that somehow lacks one or has one `chrono` cannot parse.
+fn internal_date(body: &[u8]) -> DateTime<FixedOffset> {
+    let text = String::from_utf8_lossy(body);
+
+    text.lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix("Date: ").or_else(|| line.strip_prefix("date: ")))
+        .and_then(|value| DateTime::parse_from_rfc2822(value.trim()).ok())
+        .unwrap_or_else(|| Local::now().fixed_offset())
+}
+
+/// Creates `mailbox` on the destination server, tolerating the `NO`
+/// response servers return when it already exists.
+fn ensure_mailbox<T: Read + Write>(session: &mut Session<T>, mailbox: &str) -> imap::error::Result<()> {
+    match session.create(mailbox) {
+        Ok(()) => Ok(()),
+        Err(imap::error::Error::No(_)) => Ok(()),
+        Err(error) => Err(error)
+    }
+}
+
+/// Replays every message `source` knows about onto `session`, creating
+/// mailboxes that do not already exist. Already-restored messages are
+/// skipped by consulting `manifest`, so an interrupted restore can resume
+/// without re-uploading what it already appended.
+pub fn restore_messages<T: Read + Write>(session: &mut Session<T>, multi_progress: MultiProgress, source: &mut dyn MessageSource, manifest: &mut Manifest, manifest_path: &Path) -> TupleResult {
+    let start = Local::now();
+    let mailboxes = source.mailboxes()?;
+    let count = mailboxes.len() as u64;
+    let progress = multi_progress.add(ProgressBar::new(count));
+
+    progress.set_style(PROGRESS_STYLE.clone());
+
+    let mut total: u64 = 0;
+
+    for mailbox in &mailboxes {
+        let index = progress.position() + 1;
+
+        progress.set_message(mailbox.clone());
+
+        ensure_mailbox(session, mailbox)?;
+        session.select(mailbox)?;
+
+        let messages = source.messages(mailbox)?;
+        let last_restored = manifest.get(mailbox).map(|state| state.last_uid).unwrap_or(0);
+        let mut restored = 0u64;
+
+        for message in &messages {
+            if message.uid <= last_restored { continue; }
+
+            let date = internal_date(&message.body);
+            let flags: Vec<Flag> = message.flags.iter().map(|name| to_flag(name)).collect();
+
+            session.append(mailbox, &message.body).flags(flags).internal_date(date).finish()?;
+
+            // Advance the watermark only after the append completes, so an
+            // interrupted restore resumes at the right UID.
+            manifest.set(mailbox, MailboxState { uid_validity: 0, last_uid: message.uid, highest_modseq: None });
+            manifest.save(manifest_path)?;
+
+            total += message.body.len() as u64;
+            restored += 1;
+        }
+
+        info!("{index}/{count} -> {mailbox} [{restored}/{} restored]", messages.len());
+
+        progress.inc(1);
+    }
+
+    let end = Local::now();
+    let elapsed = end - start;
+
+    progress.finish_and_clear();
+    multi_progress.remove(&progress);
+
+    Ok((total, elapsed))
+}
@@ -0,0 +1,277 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf}
+};
+
+use imap::types::Flag;
+use sha2::{Digest, Sha256};
+use tar::{Builder, Header};
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+type SinkResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A destination for fetched messages. Each `--format` choice is one
+/// implementation, so the fetch loop in `mail.rs` never needs to know
+/// whether it is writing into an archive or a directory tree. `Send` so a
+/// single sink can be shared behind a mutex by `--jobs` worker threads.
+pub trait MessageSink: Send {
+    /// Writes a single message belonging to `mailbox`, tagged with the UID
+    /// and flags it was fetched with.
+    fn write(&mut self, mailbox: &str, uid: u32, flags: &[Flag<'_>], body: &[u8]) -> SinkResult<()>;
+
+    /// Flushes and closes the sink once every mailbox has been written.
+    fn finish(&mut self) -> SinkResult<()>;
+
+    /// Removes a previously written message, used to prune mail the server
+    /// reported as `VANISHED` after a QRESYNC resync. Returns `Ok(false)`
+    /// when this format has no way to remove a single already-written
+    /// message, so the caller can report that the vanished message is still
+    /// sitting in the archive rather than silently claiming it was pruned.
+    fn remove(&mut self, _mailbox: &str, _uid: u32) -> SinkResult<bool> {
+        Ok(false)
+    }
+}
+
+/// Writes each message as `{mailbox}/{uid}-{flags}-{sha256}.eml` into a zip
+/// archive, so [`crate::source::ZipSource`] can restore it with the flags it
+/// was fetched with.
+pub struct ZipSink<W: Write + io::Seek> {
+    writer: ZipWriter<W>,
+    options: SimpleFileOptions
+}
+
+impl<W: Write + io::Seek> ZipSink<W> {
+    pub fn new(writer: ZipWriter<W>, options: SimpleFileOptions) -> Self {
+        Self { writer, options }
+    }
+}
+
+impl<W: Write + io::Seek> MessageSink for ZipSink<W> {
+    fn write(&mut self, mailbox: &str, uid: u32, flags: &[Flag<'_>], body: &[u8]) -> SinkResult<()> {
+        let path = Path::new(mailbox).join(eml_filename(uid, flags, body));
+
+        self.writer.start_file_from_path(&path, self.options)?;
+        self.writer.write_all(body)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> SinkResult<()> {
+        self.writer.finish()?;
+
+        Ok(())
+    }
+}
+
+/// Writes each message as `{mailbox}/{uid}-{flags}-{sha256}.eml` into a tar
+/// archive, so [`crate::source::TarSource`] can restore it with the flags it
+/// was fetched with.
+pub struct TarSink<W: Write> {
+    builder: Builder<W>
+}
+
+impl<W: Write> TarSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { builder: Builder::new(writer) }
+    }
+}
+
+impl<W: Write> MessageSink for TarSink<W> {
+    fn write(&mut self, mailbox: &str, uid: u32, flags: &[Flag<'_>], body: &[u8]) -> SinkResult<()> {
+        let path = Path::new(mailbox).join(eml_filename(uid, flags, body));
+        let mut header = Header::new_gnu();
+
+        header.set_size(body.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        self.builder.append_data(&mut header, path, body)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> SinkResult<()> {
+        self.builder.finish()?;
+
+        Ok(())
+    }
+}
+
+fn eml_filename(uid: u32, flags: &[Flag<'_>], body: &[u8]) -> String {
+    let mut digest = Sha256::new();
+
+    digest.update(body);
+
+    let hex = hex::encode(digest.finalize());
+    let letters = maildir_flags(flags);
+
+    format!("{uid}-{letters}-{hex}.eml")
+}
+
+/// Maps IMAP flags onto the single-letter flags maildir stores in the
+/// `:2,` suffix of a message's filename. Maildir requires these to appear
+/// in ASCII order, and ignores flags (like `\Recent`) it has no letter for.
+/// Reused by [`ZipSink`]/[`TarSink`] to stash flags in their filenames too.
+fn maildir_flags(flags: &[Flag<'_>]) -> String {
+    let mut letters: Vec<char> = flags.iter().filter_map(|flag| match flag {
+        Flag::Draft => Some('D'),
+        Flag::Flagged => Some('F'),
+        Flag::Answered => Some('R'),
+        Flag::Seen => Some('S'),
+        Flag::Deleted => Some('T'),
+        _ => None
+    }).collect();
+
+    letters.sort_unstable();
+    letters.into_iter().collect()
+}
+
+/// Flattens a `/`-separated mailbox hierarchy into a single path segment
+/// joined with `.`, e.g. `Work/Invoices` becomes `Work.Invoices` (the
+/// Maildir++ convention), so a mailbox from a server that uses `/` as its
+/// hierarchy delimiter (Gmail, default Dovecot) never needs a real nested
+/// directory to be written out.
+fn flatten_mailbox_path(mailbox: &str) -> String {
+    mailbox.replace('/', ".")
+}
+
+/// Writes each message under `{output}/{mailbox}/{cur,new,tmp}`, creating a
+/// standard maildir per mailbox so the result can be opened directly by mail
+/// clients.
+pub struct MaildirSink {
+    root: PathBuf,
+    sequence: u64
+}
+
+impl MaildirSink {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, sequence: 0 }
+    }
+
+    fn mailbox_dir(&self, mailbox: &str) -> SinkResult<PathBuf> {
+        let dir = self.root.join(flatten_mailbox_path(mailbox));
+
+        for leaf in ["cur", "new", "tmp"] {
+            fs::create_dir_all(dir.join(leaf))?;
+        }
+
+        Ok(dir)
+    }
+}
+
+impl MessageSink for MaildirSink {
+    fn write(&mut self, mailbox: &str, uid: u32, flags: &[Flag<'_>], body: &[u8]) -> SinkResult<()> {
+        let dir = self.mailbox_dir(mailbox)?;
+
+        self.sequence += 1;
+
+        let unique = format!("{}.{}.mailcopy.{uid}", self.sequence, std::process::id());
+        let info = maildir_flags(flags);
+        let filename = format!("{unique}:2,{info}");
+        let tmp_path = dir.join("tmp").join(&unique);
+        let final_path = dir.join("cur").join(&filename);
+
+        fs::write(&tmp_path, body)?;
+        fs::rename(&tmp_path, &final_path)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> SinkResult<()> {
+        Ok(())
+    }
+
+    fn remove(&mut self, mailbox: &str, uid: u32) -> SinkResult<bool> {
+        let dir = self.root.join(flatten_mailbox_path(mailbox));
+        let suffix = format!(".mailcopy.{uid}");
+
+        for leaf in ["cur", "new"] {
+            let leaf_dir = dir.join(leaf);
+
+            if !leaf_dir.is_dir() { continue; }
+
+            for entry in fs::read_dir(&leaf_dir)? {
+                let entry = entry?;
+                let filename = entry.file_name().to_string_lossy().into_owned();
+                let stem = filename.split(':').next().unwrap_or(&filename).to_string();
+
+                if stem.ends_with(&suffix) {
+                    fs::remove_file(entry.path())?;
+
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Writes each message into `{output}/{mailbox}.mbox`, one `mbox` file per
+/// mailbox, in the traditional `mboxo` style: a `From ` separator line
+/// before each message and `>`-escaping of any body line that would
+/// otherwise be mistaken for one.
+pub struct MboxSink {
+    root: PathBuf
+}
+
+impl MboxSink {
+    pub fn new(root: PathBuf) -> SinkResult<Self> {
+        fs::create_dir_all(&root)?;
+
+        Ok(Self { root })
+    }
+
+    fn open(&self, mailbox: &str) -> SinkResult<File> {
+        let path = self.root.join(format!("{}.mbox", flatten_mailbox_path(mailbox)));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(file)
+    }
+}
+
+impl MessageSink for MboxSink {
+    fn write(&mut self, mailbox: &str, _: u32, _: &[Flag<'_>], body: &[u8]) -> SinkResult<()> {
+        let mut file = self.open(mailbox)?;
+
+        writeln!(file, "From mailcopy@localhost {}", mbox_date())?;
+
+        for line in body.split(|&byte| byte == b'\n') {
+            if line.starts_with(b"From ") { file.write_all(b">")?; }
+
+            file.write_all(line)?;
+            file.write_all(b"\n")?;
+        }
+
+        file.write_all(b"\n")?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> SinkResult<()> {
+        Ok(())
+    }
+}
+
+fn mbox_date() -> String {
+    chrono::Local::now().format("%a %b %e %H:%M:%S %Y").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maildir_flags_sorts_letters_and_skips_unmapped() {
+        let flags = [Flag::Seen, Flag::Flagged, Flag::Recent, Flag::Draft];
+
+        assert_eq!(maildir_flags(&flags), "DFS");
+    }
+
+    #[test]
+    fn flatten_mailbox_path_flattens_hierarchy_into_dotted_form() {
+        assert_eq!(flatten_mailbox_path("Work/Invoices/Paid"), "Work.Invoices.Paid");
+        assert_eq!(flatten_mailbox_path("INBOX"), "INBOX");
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf}
+};
+
+use tar::Archive;
+use zip::ZipArchive;
+
+type SourceResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A single message read back out of a previously written archive, ready to
+/// be `APPEND`ed to a mailbox during a restore.
+pub struct RestoreMessage {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub body: Vec<u8>
+}
+
+/// The read-side counterpart of [`crate::sink::MessageSink`]: one
+/// implementation per `--format`, used by `--restore` to walk a previously
+/// produced archive mailbox by mailbox.
+pub trait MessageSource {
+    /// The mailboxes present in the archive, in no particular order.
+    fn mailboxes(&mut self) -> SourceResult<Vec<String>>;
+
+    /// Every message stored under `mailbox`, ordered by UID so a resumed
+    /// restore can skip everything at or below the last UID it appended.
+    fn messages(&mut self, mailbox: &str) -> SourceResult<Vec<RestoreMessage>>;
+}
+
+/// Reverses [`crate::sink::maildir_flags`]'s single-letter flag encoding
+/// back into flag names, shared by the maildir `:2,<letters>` suffix and the
+/// zip/tar `{uid}-{letters}-{sha256}.eml` filename.
+fn parse_flag_letters(letters: &str) -> Vec<String> {
+    letters.chars().filter_map(|letter| match letter {
+        'D' => Some("Draft".to_string()),
+        'F' => Some("Flagged".to_string()),
+        'R' => Some("Answered".to_string()),
+        'S' => Some("Seen".to_string()),
+        'T' => Some("Deleted".to_string()),
+        _ => None
+    }).collect()
+}
+
+/// Splits an archive entry path of the form
+/// `{mailbox}/{uid}-{flags}-{sha256}.eml` into its mailbox, UID, and flags.
+fn split_entry_path(path: &Path) -> Option<(String, u32, Vec<String>)> {
+    let mailbox = path.parent()?.to_str()?.to_string();
+    let filename = path.file_stem()?.to_str()?;
+    let (uid, rest) = filename.split_once('-')?;
+    let uid: u32 = uid.parse().ok()?;
+    let (letters, _hex) = rest.rsplit_once('-')?;
+    let flags = parse_flag_letters(letters);
+
+    Some((mailbox, uid, flags))
+}
+
+pub struct ZipSource {
+    archive: ZipArchive<File>
+}
+
+impl ZipSource {
+    pub fn open(path: &Path) -> SourceResult<Self> {
+        let file = File::open(path)?;
+        let archive = ZipArchive::new(file)?;
+
+        Ok(Self { archive })
+    }
+}
+
+impl MessageSource for ZipSource {
+    fn mailboxes(&mut self) -> SourceResult<Vec<String>> {
+        let mut mailboxes: Vec<String> = self.archive.file_names()
+            .filter_map(|name| split_entry_path(Path::new(name)).map(|(mailbox, _, _)| mailbox))
+            .collect();
+
+        mailboxes.sort_unstable();
+        mailboxes.dedup();
+
+        Ok(mailboxes)
+    }
+
+    fn messages(&mut self, mailbox: &str) -> SourceResult<Vec<RestoreMessage>> {
+        let mut messages = Vec::new();
+
+        for index in 0..self.archive.len() {
+            let mut entry = self.archive.by_index(index)?;
+            let path = PathBuf::from(entry.name());
+
+            if let Some((entry_mailbox, uid, flags)) = split_entry_path(&path) {
+                if entry_mailbox != mailbox { continue; }
+
+                let mut body = Vec::new();
+
+                entry.read_to_end(&mut body)?;
+                messages.push(RestoreMessage { uid, flags, body });
+            }
+        }
+
+        messages.sort_by_key(|message| message.uid);
+
+        Ok(messages)
+    }
+}
+
+pub struct TarSource {
+    path: PathBuf
+}
+
+impl TarSource {
+    pub fn open(path: &Path) -> SourceResult<Self> {
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl MessageSource for TarSource {
+    fn mailboxes(&mut self) -> SourceResult<Vec<String>> {
+        let mut archive = Archive::new(File::open(&self.path)?);
+        let mut mailboxes = Vec::new();
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.into_owned();
+
+            if let Some((mailbox, _, _)) = split_entry_path(&path) { mailboxes.push(mailbox); }
+        }
+
+        mailboxes.sort_unstable();
+        mailboxes.dedup();
+
+        Ok(mailboxes)
+    }
+
+    fn messages(&mut self, mailbox: &str) -> SourceResult<Vec<RestoreMessage>> {
+        let mut archive = Archive::new(File::open(&self.path)?);
+        let mut messages = Vec::new();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+
+            if let Some((entry_mailbox, uid, flags)) = split_entry_path(&path) {
+                if entry_mailbox != mailbox { continue; }
+
+                let mut body = Vec::new();
+
+                entry.read_to_end(&mut body)?;
+                messages.push(RestoreMessage { uid, flags, body });
+            }
+        }
+
+        messages.sort_by_key(|message| message.uid);
+
+        Ok(messages)
+    }
+}
+
+/// Parses the maildir `:2,<flags>` suffix back into flag names, reversing
+/// [`crate::sink::maildir_flags`].
+fn parse_maildir_flags(filename: &str) -> Vec<String> {
+    let Some((_, info)) = filename.split_once(":2,") else { return Vec::new(); };
+
+    parse_flag_letters(info)
+}
+
+/// Parses the UID out of a `{sequence}.{pid}.mailcopy.{uid}[:2,<flags>]`
+/// filename written by [`crate::sink::MaildirSink`].
+fn parse_maildir_uid(filename: &str) -> Option<u32> {
+    let name = filename.split(':').next()?;
+    let uid = name.rsplit('.').next()?;
+
+    uid.parse().ok()
+}
+
+pub struct MaildirSource {
+    root: PathBuf
+}
+
+impl MaildirSource {
+    pub fn open(root: &Path) -> SourceResult<Self> {
+        Ok(Self { root: root.to_path_buf() })
+    }
+}
+
+impl MessageSource for MaildirSource {
+    fn mailboxes(&mut self) -> SourceResult<Vec<String>> {
+        let mut mailboxes = Vec::new();
+
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+
+            if entry.path().join("cur").is_dir() {
+                mailboxes.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        mailboxes.sort_unstable();
+
+        Ok(mailboxes)
+    }
+
+    fn messages(&mut self, mailbox: &str) -> SourceResult<Vec<RestoreMessage>> {
+        let mut messages = Vec::new();
+        let dir = self.root.join(mailbox);
+
+        for leaf in ["cur", "new"] {
+            let leaf_dir = dir.join(leaf);
+
+            if !leaf_dir.is_dir() { continue; }
+
+            for entry in fs::read_dir(&leaf_dir)? {
+                let entry = entry?;
+                let filename = entry.file_name().to_string_lossy().into_owned();
+
+                if let Some(uid) = parse_maildir_uid(&filename) {
+                    let body = fs::read(entry.path())?;
+                    let flags = parse_maildir_flags(&filename);
+
+                    messages.push(RestoreMessage { uid, flags, body });
+                }
+            }
+        }
+
+        messages.sort_by_key(|message| message.uid);
+
+        Ok(messages)
+    }
+}
+
+/// Splits an `mboxo`-style file back into the individual messages
+/// [`crate::sink::MboxSink`] wrote, reversing its `>`-escaping of any body
+/// line that starts with `From `. Flags are not recorded by the mbox
+/// format, so restored messages always come back unflagged.
+fn split_mbox(contents: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let contents = contents.strip_suffix(b"\n").unwrap_or(contents);
+
+    for line in contents.split(|&byte| byte == b'\n') {
+        if line.starts_with(b"From ") {
+            if let Some(message) = current.take() { messages.push(message); }
+
+            current = Some(Vec::new());
+
+            continue;
+        }
+
+        if let Some(message) = current.as_mut() {
+            let unescaped = line.strip_prefix(b">From ").map(|rest| [b"From ".as_slice(), rest].concat());
+
+            message.extend_from_slice(unescaped.as_deref().unwrap_or(line));
+            message.push(b'\n');
+        }
+    }
+
+    if let Some(message) = current {
+        if !message.is_empty() { messages.push(message); }
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_maildir_flags_reverses_maildir_flags() {
+        let flags = parse_maildir_flags("1.123.mailcopy.5:2,DFS");
+
+        assert_eq!(flags, vec!["Draft", "Flagged", "Seen"]);
+    }
+
+    #[test]
+    fn parse_maildir_flags_empty_without_info_suffix() {
+        assert!(parse_maildir_flags("1.123.mailcopy.5").is_empty());
+    }
+
+    #[test]
+    fn parse_maildir_uid_reads_uid_before_the_info_suffix() {
+        assert_eq!(parse_maildir_uid("1.123.mailcopy.42:2,S"), Some(42));
+        assert_eq!(parse_maildir_uid("1.123.mailcopy.42"), Some(42));
+    }
+
+    #[test]
+    fn split_entry_path_reads_mailbox_uid_and_flags() {
+        let path = Path::new("Work/Invoices/42-FS-abc123.eml");
+        let (mailbox, uid, flags) = split_entry_path(path).unwrap();
+
+        assert_eq!(mailbox, "Work/Invoices");
+        assert_eq!(uid, 42);
+        assert_eq!(flags, vec!["Flagged", "Seen"]);
+    }
+
+    #[test]
+    fn split_entry_path_handles_unflagged_messages() {
+        let path = Path::new("INBOX/7--abc123.eml");
+        let (mailbox, uid, flags) = split_entry_path(path).unwrap();
+
+        assert_eq!(mailbox, "INBOX");
+        assert_eq!(uid, 7);
+        assert!(flags.is_empty());
+    }
+
+    #[test]
+    fn split_mbox_splits_on_from_lines_and_unescapes_body() {
+        let contents = b"From a@b 1\nHello\n>From inside body\n\nFrom a@b 2\nWorld\n";
+        let messages = split_mbox(contents);
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], b"Hello\nFrom inside body\n\n".to_vec());
+        assert_eq!(messages[1], b"World\n".to_vec());
+    }
+}
+
+pub struct MboxSource {
+    root: PathBuf
+}
+
+impl MboxSource {
+    pub fn open(root: &Path) -> SourceResult<Self> {
+        Ok(Self { root: root.to_path_buf() })
+    }
+}
+
+impl MessageSource for MboxSource {
+    fn mailboxes(&mut self) -> SourceResult<Vec<String>> {
+        let mut mailboxes = Vec::new();
+
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.extension().is_some_and(|extension| extension == "mbox") {
+                if let Some(mailbox) = path.file_stem() {
+                    mailboxes.push(mailbox.to_string_lossy().into_owned());
+                }
+            }
+        }
+
+        mailboxes.sort_unstable();
+
+        Ok(mailboxes)
+    }
+
+    fn messages(&mut self, mailbox: &str) -> SourceResult<Vec<RestoreMessage>> {
+        let path = self.root.join(format!("{mailbox}.mbox"));
+        let contents = fs::read(path)?;
+
+        // Mbox does not preserve UIDs; messages are replayed in file order
+        // and numbered sequentially so resuming can still skip a prefix.
+        let messages = split_mbox(&contents).into_iter()
+            .enumerate()
+            .map(|(index, body)| RestoreMessage { uid: index as u32 + 1, flags: Vec::new(), body })
+            .collect();
+
+        Ok(messages)
+    }
+}